@@ -1,10 +1,18 @@
 pub enum Event {
 	/// A new object has been inserted in the scene.
-	New(usize),
+	///
+	/// Carries the slot `index` the object was inserted into along with the
+	/// slot's current `generation`, so consumers can tell it apart from any
+	/// previous object that occupied the same slot.
+	New(usize, u64),
 
 	/// An object has been dropped.
-	/// 
-	/// This event will always be emitted before any `New` event with the same id
-	/// that would replace the recently dropped object.
-	Drop(usize)
-}
\ No newline at end of file
+	///
+	/// This event will always be emitted before any `New` event with the same
+	/// index that would replace the recently dropped object.
+	///
+	/// Carries the `generation` the dropped object was inserted with, so
+	/// consumers holding on to a stale `(index, generation)` pair can tell
+	/// this `Drop` apart from one concerning a slot that was since reused.
+	Drop(usize, u64)
+}
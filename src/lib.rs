@@ -1,7 +1,9 @@
 mod event;
 mod scene;
+mod concurrent_scene;
 mod map;
 
 pub use event::*;
 pub use scene::*;
+pub use concurrent_scene::{ConcurrentScene, ConcurrentId, Guard};
 pub use map::Map;
\ No newline at end of file
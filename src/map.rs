@@ -1,11 +1,22 @@
 use std::marker::PhantomData;
 use crate::{
+	Event,
 	Id,
 	WeakId
 };
 
 pub trait Key<K>: Copy {
 	fn index(&self) -> usize;
+
+	/// Generation the slot at `index` must have for this key to resolve.
+	///
+	/// `None` means "don't check" — a plain `usize` index carries no
+	/// generation information, so it resolves to whatever currently
+	/// occupies the slot.
+	#[inline]
+	fn generation(&self) -> Option<u64> {
+		None
+	}
 }
 
 impl<'a, K> Key<K> for &'a Id<K> {
@@ -13,6 +24,11 @@ impl<'a, K> Key<K> for &'a Id<K> {
 	fn index(&self) -> usize {
 		Id::index(self)
 	}
+
+	#[inline]
+	fn generation(&self) -> Option<u64> {
+		Some(Id::generation(self))
+	}
 }
 
 impl<'a, K> Key<K> for &'a WeakId<K> {
@@ -20,17 +36,37 @@ impl<'a, K> Key<K> for &'a WeakId<K> {
 	fn index(&self) -> usize {
 		WeakId::index(self)
 	}
+
+	#[inline]
+	fn generation(&self) -> Option<u64> {
+		Some(WeakId::generation(self))
+	}
 }
 
-impl<'a, K> Key<K> for usize {
+impl<K> Key<K> for usize {
 	#[inline]
 	fn index(&self) -> usize {
 		*self
 	}
 }
 
+struct Slot<T> {
+	generation: u64,
+	value: T
+}
+
+/// A dense secondary store keyed by a scene's `Id<K>`/`WeakId<K>`.
+///
+/// `Map` itself does not know about a particular `Scene`: it only indexes
+/// slots by position. To stay correct when a scene object is dropped and
+/// its slot reused by a new one, feed the scene's events to `apply_events`
+/// from time to time (e.g. right after `Scene::garbage_collect`) so stale
+/// entries are pruned, and always look entries up through an `Id`/`WeakId`
+/// rather than a raw `usize` so a generation mismatch is caught instead of
+/// silently handing back the wrong value.
 pub struct Map<K, T> {
-	data: Vec<Option<T>>,
+	data: Vec<Option<Slot<T>>>,
+	len: usize,
 	k: PhantomData<K>
 }
 
@@ -38,25 +74,153 @@ impl<K, T> Map<K, T> {
 	pub fn new() -> Map<K, T> {
 		Map {
 			data: Vec::new(),
+			len: 0,
 			k: PhantomData
 		}
 	}
 
+	/// Number of occupied slots.
+	#[inline]
+	pub fn len(&self) -> usize {
+		self.len
+	}
+
+	#[inline]
+	pub fn is_empty(&self) -> bool {
+		self.len == 0
+	}
+
 	pub fn get<I>(&self, id: I) -> Option<&T> where I: Key<K> {
-		self.data.get(id.index()).map(|o| o.as_ref()).flatten()
+		let slot = self.data.get(id.index())?.as_ref()?;
+
+		if let Some(generation) = id.generation() {
+			if slot.generation != generation {
+				return None
+			}
+		}
+
+		Some(&slot.value)
 	}
 
 	pub fn get_mut<I>(&mut self, id: I) -> Option<&mut T> where I: Key<K> {
-		self.data.get_mut(id.index()).map(|o| o.as_mut()).flatten()
+		let generation = id.generation();
+		let slot = self.data.get_mut(id.index())?.as_mut()?;
+
+		if let Some(generation) = generation {
+			if slot.generation != generation {
+				return None
+			}
+		}
+
+		Some(&mut slot.value)
 	}
 
 	pub fn set<I>(&mut self, id: I, t: T) -> Option<T> where I: Key<K> {
 		if self.data.len() <= id.index() {
 			self.data.resize_with(id.index()+1, || None);
 		}
-		
-		let mut result = Some(t);
+
+		let mut result = Some(Slot {
+			generation: id.generation().unwrap_or(0),
+			value: t
+		});
 		std::mem::swap(&mut self.data[id.index()], &mut result);
-		result
+
+		match result {
+			Some(_) => result.map(|slot| slot.value),
+			None => {
+				self.len += 1;
+				None
+			}
+		}
+	}
+
+	/// Remove the entry at `id`, if any, regardless of its generation.
+	pub fn remove<I>(&mut self, id: I) -> Option<T> where I: Key<K> {
+		let slot = self.data.get_mut(id.index())?.take()?;
+		self.len -= 1;
+		Some(slot.value)
 	}
-}
\ No newline at end of file
+
+	pub fn iter(&self) -> impl Iterator<Item=(usize, &T)> {
+		self.data.iter().enumerate().filter_map(|(i, slot)| slot.as_ref().map(|slot| (i, &slot.value)))
+	}
+
+	pub fn iter_mut(&mut self) -> impl Iterator<Item=(usize, &mut T)> {
+		self.data.iter_mut().enumerate().filter_map(|(i, slot)| slot.as_mut().map(|slot| (i, &mut slot.value)))
+	}
+
+	pub fn keys(&self) -> impl Iterator<Item=usize> + '_ {
+		self.data.iter().enumerate().filter_map(|(i, slot)| slot.is_some().then_some(i))
+	}
+
+	pub fn values(&self) -> impl Iterator<Item=&T> {
+		self.data.iter().filter_map(|slot| slot.as_ref().map(|slot| &slot.value))
+	}
+
+	/// Apply a scene's events, pruning entries whose object has been
+	/// dropped.
+	///
+	/// Only `Event::Drop` is acted upon: it clears the corresponding slot so
+	/// a later `Event::New` reusing the same index starts from an empty
+	/// entry rather than inheriting the previous object's value. The slot
+	/// is only cleared if it still holds the dropped generation: if a
+	/// `Drop` for an old generation and a `set` (or a later `Drop`) for the
+	/// slot's current generation end up in the same batch of events, the
+	/// stale `Drop` must not wipe out the current entry.
+	pub fn apply_events(&mut self, events: &[Event]) {
+		for event in events {
+			if let Event::Drop(index, generation) = *event {
+				if let Some(slot) = self.data.get_mut(index) {
+					if slot.as_ref().is_some_and(|slot| slot.generation == generation) {
+						slot.take();
+						self.len -= 1;
+					}
+				}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[derive(Clone, Copy)]
+	struct Slot(usize, u64);
+
+	impl Key<()> for Slot {
+		#[inline]
+		fn index(&self) -> usize {
+			self.0
+		}
+
+		#[inline]
+		fn generation(&self) -> Option<u64> {
+			Some(self.1)
+		}
+	}
+
+	#[test]
+	fn apply_events_prunes_matching_generation_drop() {
+		let mut map: Map<(), u32> = Map::new();
+		map.set(Slot(0, 1), 42);
+
+		map.apply_events(&[Event::Drop(0, 1)]);
+
+		assert!(map.get(Slot(0, 1)).is_none());
+		assert_eq!(map.len(), 0);
+	}
+
+	#[test]
+	fn apply_events_ignores_stale_generation_drop() {
+		let mut map: Map<(), u32> = Map::new();
+		map.set(Slot(0, 2), 99);
+
+		// A `Drop` for an older generation must not prune the current entry.
+		map.apply_events(&[Event::Drop(0, 1)]);
+
+		assert_eq!(map.get(Slot(0, 2)), Some(&99));
+		assert_eq!(map.len(), 1);
+	}
+}
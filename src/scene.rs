@@ -25,15 +25,59 @@ use slab::Slab;
 use crossbeam_queue::SegQueue;
 use crate::Event;
 
+/// Number of bits of an `Id`'s packed key dedicated to the slot `index`.
+///
+/// The remaining 64 - `INDEX_BITS` high bits are used for the `generation`
+/// counter, i.e. only the low 32 bits of a generation are actually stored
+/// in the packed key (a generation wrapping past `u32::MAX` ambiguates with
+/// an earlier one 2^32 generations apart — not a concern in practice, since
+/// a single slot would need to be reused that many times). `Entry::generation`
+/// itself stays a full `u64` so this split is easy to widen later without
+/// touching its storage. This follows the bit-packing scheme used by
+/// `sharded-slab`'s `Pack`: a single `u64` is split into an index part and a
+/// generation part instead of carrying them as two separate fields, so
+/// `Id`/`WeakId` stay the size of one machine word on top of the
+/// `Arc`/`Weak` handle.
+const INDEX_BITS: u32 = 32;
+
+/// Mask selecting the `index` bits of a packed key.
+const INDEX_MASK: u64 = (1 << INDEX_BITS) - 1;
+
+#[inline]
+fn pack(index: usize, generation: u64) -> u64 {
+	(generation << INDEX_BITS) | (index as u64 & INDEX_MASK)
+}
+
+#[inline]
+fn unpack(key: u64) -> (usize, u64) {
+	((key & INDEX_MASK) as usize, key >> INDEX_BITS)
+}
+
 struct State {
-	grabs: SegQueue<usize>,
-	releases: SegQueue<usize>
+	/// Pending grabs/releases, keyed by the same packed `(index, generation)`
+	/// `Id`'s own key uses — not a plain slot index.
+	///
+	/// Carrying the generation lets `garbage_collect` recognize and ignore a
+	/// grab/release meant for a slot that `retain`/`drain_filter` has since
+	/// force-removed (and possibly let a new object reuse): without it, a
+	/// stale entry would either panic against a now-empty slot or silently
+	/// mutate the refcount of whatever unrelated object moved in.
+	grabs: SegQueue<u64>,
+	releases: SegQueue<u64>
 }
 
 pub struct Scene<T, E> {
 	/// Scene objects.
 	slab: Slab<Entry<T>>,
 
+	/// Generation of each slot, indexed by `index`.
+	///
+	/// Unlike `slab`, this never shrinks: a slot's generation must survive
+	/// its entry being removed so that a later `insert` reusing the slot can
+	/// bump it, and so `WeakId::upgrade` can tell the old and new occupants
+	/// of the slot apart.
+	generations: Vec<u64>,
+
 	/// Grabs and releases.
 	state: Arc<State>,
 
@@ -45,6 +89,7 @@ impl<T, E> Scene<T, E> {
 	pub fn new() -> Scene<T, E> {
 		Scene {
 			slab: Slab::new(),
+			generations: Vec::new(),
 			state: Arc::new(State {
 				grabs: SegQueue::new(),
 				releases: SegQueue::new()
@@ -64,15 +109,30 @@ impl<T, E> Scene<T, E> {
 	}
 
 	pub fn id(&self, index: usize) -> Option<Id<T>> {
-		self.slab.get(index).map(|_| {
-			self.state.grabs.push(index);
-			Id(self.state.clone(), index, PhantomData)
+		self.slab.get(index).map(|entry| {
+			let key = pack(index, entry.generation);
+			self.state.grabs.push(key);
+			Id(self.state.clone(), key, PhantomData)
 		})
 	}
 
+	/// Build an `Id` for `index` without queuing a grab for it.
+	///
+	/// For internal use by `retain`/`drain_filter`, which hand a live slot's
+	/// `Id` to a caller-supplied predicate purely for identification and
+	/// never let it escape. Since it doesn't go through the `grabs` queue,
+	/// the caller must dispose of it with `Id::discard_without_release`
+	/// rather than letting it `Drop` — otherwise it would queue a release
+	/// with no matching grab, corrupting that slot's refcount.
+	fn temporary_id(&self, index: usize) -> Option<Id<T>> {
+		self.slab.get(index).map(|entry| Id(self.state.clone(), pack(index, entry.generation), PhantomData))
+	}
+
 	pub fn get<'a>(&'a self, id: &'a Id<T>) -> Ref<'a, T> {
 		assert!(Arc::ptr_eq(&id.0, &self.state));
-		let entry = self.slab.get(id.1).unwrap();
+		let (index, generation) = unpack(id.1);
+		let entry = self.slab.get(index).unwrap();
+		assert_eq!(entry.generation, generation, "stale Id: slot has been recycled");
 		Ref {
 			entry, id
 		}
@@ -80,48 +140,198 @@ impl<T, E> Scene<T, E> {
 
 	pub fn get_mut<'a>(&'a mut self, id: &'a Id<T>) -> Mut<'a, T> {
 		assert!(Arc::ptr_eq(&id.0, &self.state));
-		let entry = self.slab.get_mut(id.1).unwrap();
+		let (index, generation) = unpack(id.1);
+		let entry = self.slab.get_mut(index).unwrap();
+		assert_eq!(entry.generation, generation, "stale Id: slot has been recycled");
 		Mut {
 			entry, id
 		}
 	}
 }
 
+impl<T, E> Drop for Scene<T, E> {
+	/// Tear the scene down without tripping `Entry`'s refcount assertion.
+	///
+	/// Dropping the slab directly would drop every remaining `Entry`
+	/// regardless of its `refs` count, firing the `assert!(self.refs == 0)`
+	/// in `Entry::drop` for any object that still has live `Id`s pointing to
+	/// it. Instead we pull each entry's data out through `into_data`, which
+	/// discards the `Entry` wrapper (and its refcount) without running its
+	/// destructor.
+	///
+	/// Entries are dropped from the highest slot index down to the lowest.
+	/// For entries that haven't been freed and reused in between, that
+	/// coincides with reverse insertion order, matching the order Rust
+	/// itself drops local variables in — so a `T` whose `Drop` impl reaches
+	/// back into the scene (through a `WeakId`) sees a predictable teardown
+	/// sequence.
+	///
+	/// Any `Id`s still held by the caller after this simply become dangling:
+	/// their own `Drop` only pushes to the (still live, `Arc`-owned) release
+	/// queue, which nothing will ever drain again, and `WeakId::upgrade`
+	/// already returns `None` once the underlying state is gone.
+	///
+	/// The drop order itself isn't configurable yet — this reverse-slot-index
+	/// default is the only one offered for now.
+	fn drop(&mut self) {
+		let mut indices: Vec<usize> = self.slab.iter().map(|(index, _)| index).collect();
+		indices.sort_unstable_by(|a, b| b.cmp(a));
+
+		for index in indices {
+			if let Some(entry) = self.slab.try_remove(index) {
+				drop(entry.into_data());
+			}
+		}
+	}
+}
+
 impl<T, E> Scene<T, E> where Event: Into<E> {
 	/// Remove unused entities.
-	/// 
+	///
 	/// Use must call this function from time to time
 	/// to limit memory usage.
-	/// 
+	///
 	/// This may emit new `Drop` events,
 	/// so be sure not to call `clear_events` before
 	/// having handled those events.
 	pub fn garbage_collect(&mut self) {
-		while let Some(id) = self.state.grabs.pop() {
-			self.slab.get_mut(id).unwrap().grab()
+		while let Some(key) = self.state.grabs.pop() {
+			let (index, generation) = unpack(key);
+
+			if let Some(entry) = self.slab.get_mut(index) {
+				if entry.generation == generation {
+					entry.grab()
+				}
+			}
 		}
 
-		while let Some(id) = self.state.releases.pop() {
-			if self.slab.get_mut(id).unwrap().release() {
-				self.slab.remove(id);
-				self.events.push(Event::Drop(id).into());
+		while let Some(key) = self.state.releases.pop() {
+			let (index, generation) = unpack(key);
+
+			if let Some(entry) = self.slab.get_mut(index) {
+				if entry.generation == generation && entry.release() {
+					let generation = self.slab.remove(index).generation;
+					self.generations[index] = generation.wrapping_add(1);
+					self.events.push(Event::Drop(index, generation).into());
+				}
 			}
 		}
 	}
 
 	pub fn insert(&mut self, t: T) -> Id<T> {
-		let id = self.slab.insert(Entry {
+		let entry = self.slab.vacant_entry();
+		let index = entry.key();
+
+		if index >= self.generations.len() {
+			self.generations.resize(index + 1, 0);
+		}
+
+		// Only the bits that fit in the packed key, so `Id`s built from it
+		// keep matching the entry once the counter passes `u32::MAX`.
+		let generation = self.generations[index] & (u64::MAX >> INDEX_BITS);
+		entry.insert(Entry {
 			data: t,
-			refs: 1
+			refs: 1,
+			generation
 		});
-		self.events.push(Event::New(id).into());
-		Id(self.state.clone(), id, PhantomData)
+
+		self.events.push(Event::New(index, generation).into());
+		Id(self.state.clone(), pack(index, generation), PhantomData)
+	}
+
+	/// Keep only the objects for which `f` returns `true`, removing the rest
+	/// right away.
+	///
+	/// This is the "delete now" escape hatch: unlike `garbage_collect`,
+	/// removal here does not wait for every `Id` to an object to be dropped.
+	/// Any `Id`/`WeakId` still pointing at a removed object becomes
+	/// dangling; holders must check it with `WeakId::upgrade` (or compare
+	/// `Id::generation` against a freshly fetched one) rather than assume it
+	/// is still valid.
+	pub fn retain(&mut self, mut f: impl FnMut(&Id<T>, &mut T) -> bool) {
+		let indices: Vec<usize> = self.slab.iter().map(|(index, _)| index).collect();
+
+		for index in indices {
+			let id = match self.temporary_id(index) {
+				Some(id) => id,
+				None => continue
+			};
+
+			let keep = f(&id, &mut self.slab.get_mut(index).unwrap().data);
+			id.discard_without_release();
+
+			if !keep {
+				let entry = self.slab.remove(index);
+				self.generations[index] = entry.generation.wrapping_add(1);
+				self.events.push(Event::Drop(index, entry.generation).into());
+				drop(entry.into_data());
+			}
+		}
+	}
+
+	/// Like `retain`, but keeping the objects rejected by `f` instead of
+	/// discarding them: returns an iterator yielding each removed `T` so
+	/// callers can reuse it.
+	///
+	/// Removal happens lazily, as the returned iterator is driven; objects
+	/// are visited in slot order and removing one does not disturb the
+	/// traversal of the rest. Dropping the iterator without exhausting it
+	/// simply stops the scan early — anything not yet visited is left in
+	/// place.
+	pub fn drain_filter<F>(&mut self, f: F) -> DrainFilter<'_, T, E, F>
+	where F: FnMut(&Id<T>, &mut T) -> bool {
+		let indices: Vec<usize> = self.slab.iter().map(|(index, _)| index).collect();
+
+		DrainFilter {
+			scene: self,
+			indices: indices.into_iter(),
+			f
+		}
+	}
+}
+
+/// Iterator returned by `Scene::drain_filter`.
+pub struct DrainFilter<'a, T, E, F> {
+	scene: &'a mut Scene<T, E>,
+	indices: std::vec::IntoIter<usize>,
+	f: F
+}
+
+impl<'a, T, E, F> Iterator for DrainFilter<'a, T, E, F>
+where Event: Into<E>, F: FnMut(&Id<T>, &mut T) -> bool {
+	type Item = T;
+
+	fn next(&mut self) -> Option<T> {
+		for index in &mut self.indices {
+			let id = match self.scene.temporary_id(index) {
+				Some(id) => id,
+				None => continue
+			};
+
+			let keep = (self.f)(&id, &mut self.scene.slab.get_mut(index).unwrap().data);
+			id.discard_without_release();
+
+			if !keep {
+				let entry = self.scene.slab.remove(index);
+				self.scene.generations[index] = entry.generation.wrapping_add(1);
+				self.scene.events.push(Event::Drop(index, entry.generation).into());
+				return Some(entry.into_data())
+			}
+		}
+
+		None
 	}
 }
 
 struct Entry<T> {
 	data: T,
-	refs: usize
+	refs: usize,
+
+	/// Generation this entry was inserted with.
+	///
+	/// Used to tell this object apart from whatever object previously (or
+	/// will later) occupy the same slab slot.
+	generation: u64
 }
 
 impl<T> Entry<T> {
@@ -135,6 +345,16 @@ impl<T> Entry<T> {
 		self.refs -= 1;
 		self.refs == 0
 	}
+
+	/// Extract the entry's data without running `Entry`'s own `Drop` impl,
+	/// and so without tripping its refcount assertion.
+	///
+	/// Used by `Scene`'s teardown, which removes entries regardless of how
+	/// many `Id`s still reference them.
+	fn into_data(self) -> T {
+		let this = std::mem::ManuallyDrop::new(self);
+		unsafe { std::ptr::read(&this.data) }
+	}
 }
 
 impl<T> Drop for Entry<T> {
@@ -236,18 +456,43 @@ impl<'a, T> AsMut<T> for Mut<'a, T> {
 	}
 }
 
-pub struct Id<T>(Arc<State>, usize, PhantomData<T>);
+/// Identifies an object in a `Scene`.
+///
+/// The underlying packed key carries both the slab `index` and the slot's
+/// `generation` at the time this `Id` was created, so two `Id`s referring to
+/// different objects that happened to land in the same slot never compare
+/// equal, and `WeakId::upgrade` can detect that the slot has been recycled.
+pub struct Id<T>(Arc<State>, u64, PhantomData<T>);
 
 impl<T> Id<T> {
 	#[inline]
 	pub fn index(&self) -> usize {
-		self.1
+		unpack(self.1).0
+	}
+
+	#[inline]
+	pub fn generation(&self) -> u64 {
+		unpack(self.1).1
 	}
 
 	#[inline]
 	pub fn downgrade(&self) -> WeakId<T> {
 		WeakId(Arc::downgrade(&self.0), self.1, PhantomData)
 	}
+
+	/// Drop `self` without queuing a release, for an `Id` built by
+	/// `Scene::temporary_id` that never had a matching grab queued for it.
+	///
+	/// `std::mem::forget` would do the same for the queue, but it would also
+	/// skip dropping the `Arc<State>` field, leaking a strong reference on
+	/// every call. This instead runs `Id`'s own `Drop` glue for everything
+	/// except the part that pushes to `releases`: it pulls the `Arc` out
+	/// from under a `ManuallyDrop` wrapper (bypassing `Id::drop` entirely,
+	/// since it never runs for a `ManuallyDrop`) and drops that directly.
+	fn discard_without_release(self) {
+		let this = std::mem::ManuallyDrop::new(self);
+		drop(unsafe { std::ptr::read(&this.0) });
+	}
 }
 
 impl<T> Clone for Id<T> {
@@ -281,18 +526,36 @@ impl<T> Hash for Id<T> {
 	}
 }
 
-pub struct WeakId<T>(Weak<State>, usize, PhantomData<T>);
+/// A weak reference to an object in a `Scene`.
+///
+/// Like `Id`, it carries a packed `(index, generation)` key, but does not
+/// keep the object alive. `upgrade` compares the stored generation against
+/// the slot's current generation and returns `None` if the slot has since
+/// been freed and reused by another object.
+pub struct WeakId<T>(Weak<State>, u64, PhantomData<T>);
 
 impl<T> WeakId<T> {
 	#[inline]
 	pub fn index(&self) -> usize {
-		self.1
+		unpack(self.1).0
 	}
 
 	#[inline]
-	pub fn upgrade<E>(&self, lib: &Scene<T, E>) -> Option<Id<T>> {
-		let arc = self.0.upgrade().unwrap();
-		lib.slab.get(self.1).map(|_| Id(arc, self.1, PhantomData))
+	pub fn generation(&self) -> u64 {
+		unpack(self.1).1
+	}
+
+	pub fn upgrade<E>(&self, scene: &Scene<T, E>) -> Option<Id<T>> {
+		let state = self.0.upgrade()?;
+		let (index, generation) = unpack(self.1);
+		let entry = scene.slab.get(index)?;
+
+		if entry.generation != generation {
+			return None
+		}
+
+		state.grabs.push(self.1);
+		Some(Id(state, self.1, PhantomData))
 	}
 }
 
@@ -317,4 +580,94 @@ impl<T> Hash for WeakId<T> {
 	fn hash<H: Hasher>(&self, h: &mut H) {
 		self.1.hash(h)
 	}
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn weak_id_does_not_upgrade_after_slot_recycled() {
+		let mut scene: Scene<u32, Event> = Scene::new();
+		let a = scene.insert(1);
+		let weak = a.downgrade();
+
+		// Force-remove `a`'s slot and let a new object reuse it.
+		scene.retain(|id, _| id != &a);
+		scene.insert(2);
+
+		assert!(weak.upgrade(&scene).is_none());
+	}
+
+	#[test]
+	#[should_panic(expected = "stale Id")]
+	fn get_panics_on_stale_id_after_slot_recycled() {
+		let mut scene: Scene<u32, Event> = Scene::new();
+		let a = scene.insert(1);
+
+		scene.retain(|id, _| id != &a);
+		scene.insert(2);
+
+		scene.get(&a);
+	}
+
+	#[test]
+	fn drop_does_not_panic_with_live_ids_outstanding() {
+		let mut scene: Scene<u32, Event> = Scene::new();
+		let a = scene.insert(1);
+		let _b = scene.insert(2);
+
+		drop(scene);
+		drop(a);
+	}
+
+	#[test]
+	fn drop_order_is_reverse_slot_index() {
+		use std::{cell::RefCell, rc::Rc};
+
+		struct RecordDrop(usize, Rc<RefCell<Vec<usize>>>);
+
+		impl Drop for RecordDrop {
+			fn drop(&mut self) {
+				RefCell::borrow_mut(&self.1).push(self.0);
+			}
+		}
+
+		let order = Rc::new(RefCell::new(Vec::new()));
+		let mut scene: Scene<RecordDrop, Event> = Scene::new();
+		scene.insert(RecordDrop(0, order.clone()));
+		scene.insert(RecordDrop(1, order.clone()));
+		scene.insert(RecordDrop(2, order.clone()));
+
+		drop(scene);
+
+		assert_eq!(*RefCell::borrow(&order), vec![2, 1, 0]);
+	}
+
+	#[test]
+	fn retain_force_removes_regardless_of_refcount_and_emits_drop() {
+		let mut scene: Scene<u32, Event> = Scene::new();
+		let a = scene.insert(1);
+		let _a_extra = a.clone();
+		let b = scene.insert(2);
+
+		scene.retain(|id, _| id != &a);
+
+		assert!(scene.events().iter().any(|event| matches!(event, Event::Drop(index, _) if *index == a.index())));
+		assert_eq!(*scene.get(&b), 2);
+	}
+
+	#[test]
+	fn drain_filter_removes_rejected_and_preserves_the_rest() {
+		let mut scene: Scene<u32, Event> = Scene::new();
+		let a = scene.insert(10);
+		let b = scene.insert(20);
+		let c = scene.insert(30);
+
+		let removed: Vec<u32> = scene.drain_filter(|id, _| id != &b).collect();
+
+		assert_eq!(removed, vec![20]);
+		assert_eq!(*scene.get(&a), 10);
+		assert_eq!(*scene.get(&c), 30);
+	}
+}